@@ -9,6 +9,7 @@ extern crate clap;
 extern crate failure;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate nix;
 extern crate regex;
 #[macro_use]
@@ -43,12 +44,37 @@ type Result<T> = ::std::result::Result<T, failure::Error>;
 #[no_mangle]
 pub static malloc_conf: &str = "background_thread:false\0";
 
-/// flock a file descriptor of the given type within timeout_sec.
+/// Whether a `flock_with_timeout` call wants a shared (many readers)
+/// or exclusive (single writer) lock, mirroring the classic
+/// multi-reader/single-writer process-locker pattern.
+#[derive(Clone, Copy, PartialEq)]
+enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// The command-line flags that govern how a mount gets unmounted,
+/// bundled up so they can be threaded through `process_namespace` /
+/// `clean_namespaces` / `unmount_in_namespace` as a single argument
+/// instead of four.
+#[derive(Clone, Copy)]
+struct UnmountOptions {
+    dryrun: bool,
+    lazy: bool,
+    force: bool,
+    isolate_propagation: bool,
+}
+
+/// flock a file descriptor with the given mode within timeout_sec.
 /// Return True if successful.
-fn flock_with_timeout(fd: RawFd, timeout_sec: u64) -> bool {
+fn flock_with_timeout(fd: RawFd, timeout_sec: u64, mode: LockMode) -> bool {
+    let arg = match mode {
+        LockMode::Shared => nix::fcntl::FlockArg::LockSharedNonblock,
+        LockMode::Exclusive => nix::fcntl::FlockArg::LockExclusiveNonblock,
+    };
     let start = Instant::now();
     while start.elapsed().as_secs() < timeout_sec {
-        let lock = nix::fcntl::flock(fd, nix::fcntl::FlockArg::LockExclusiveNonblock);
+        let lock = nix::fcntl::flock(fd, arg);
         if !lock.is_err() {
             return true;
         }
@@ -68,13 +94,13 @@ fn test_flock_with_timeout() {
     // re-lock it until closing the original file.
     let tf1 = tempfile::NamedTempFile::new().unwrap();
     let fd1 = tf1.as_raw_fd();
-    assert!(flock_with_timeout(fd1, 10));
+    assert!(flock_with_timeout(fd1, 10, LockMode::Exclusive));
 
     // We have to re-open rather than dup because dup copies the lock,
     // too.
     let tf2 = File::open(tf1.path()).unwrap();
     let fd2 = tf2.as_raw_fd();
-    assert!(!flock_with_timeout(fd2, 1));
+    assert!(!flock_with_timeout(fd2, 1, LockMode::Exclusive));
 
     // Drop the tempfile, which closes the fd; ensure we can now
     // perform a lock.
@@ -82,7 +108,7 @@ fn test_flock_with_timeout() {
     let fd3 = tf3.as_raw_fd();
     drop(tf1);
 
-    assert!(flock_with_timeout(fd3, 1));
+    assert!(flock_with_timeout(fd3, 1, LockMode::Exclusive));
 }
 
 #[derive(Clone)]
@@ -114,10 +140,18 @@ fn get_mount_namespaces() -> Result<Vec<MountNamespaceInfo>> {
             Ok(st) => st.st_ino(),
             Err(_) => continue,
         };
-        let chroot_path = match fs::read_link(PathBuf::from(format!("/proc/{}/root", entry_name))) {
-            Ok(path) => path,
-            Err(_) => continue,
-        };
+        // `/proc/{pid}/root` is a magic symlink: the kernel resolves
+        // it to the process' actual root on every traversal, not just
+        // once.  We deliberately use that path itself as the chroot
+        // prefix rather than `fs::read_link`-ing it, since the
+        // resolved target is only meaningful from our own root and
+        // can't be reconstructed as a plain path in a pivot_root'd
+        // container.  We still probe it here so processes whose root
+        // we can't see are skipped up front.
+        let chroot_path = PathBuf::from(format!("/proc/{}/root", entry_name));
+        if fs::read_link(&chroot_path).is_err() {
+            continue;
+        }
         namespace_dedup.insert(
             inode,
             MountNamespaceInfo {
@@ -136,42 +170,114 @@ struct MountedFilesystem {
     mountpoint: String,
     chroot: PathBuf,
     fstype: String,
+    mount_id: u64,
+    parent_id: u64,
+    propagation: Propagation,
+}
+
+/// The mount propagation type of a mount, parsed from the optional
+/// fields of its `/proc/{pid}/mountinfo` entry.  A `Shared` mount
+/// forwards mount/unmount events to its peer group, so unmounting it
+/// from one mount namespace can tear down the same XAR mounted in
+/// another namespace that is still legitimately using it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Propagation {
+    Private,
+    Shared(u32),
+    Slave(u32),
+    Unbindable,
+}
+
+impl Propagation {
+    fn parse(optional_fields: &[&str]) -> Propagation {
+        for field in optional_fields {
+            if let Some(id) = field.strip_prefix("shared:") {
+                if let Ok(id) = id.parse() {
+                    return Propagation::Shared(id);
+                }
+            } else if let Some(id) = field.strip_prefix("master:") {
+                if let Ok(id) = id.parse() {
+                    return Propagation::Slave(id);
+                }
+            } else if *field == "unbindable" {
+                return Propagation::Unbindable;
+            }
+        }
+        Propagation::Private
+    }
+
+    fn is_shared(&self) -> bool {
+        matches!(self, Propagation::Shared(_))
+    }
+}
+
+/// mtab (and mountinfo) fields can be escaped; fix them up before
+/// calling umount.  Details:
+/// https://gnu.org/software/libc/manual/html_node/mtab.html
+/// Note backslashes are just '\134' and not '\0134' - special case.
+fn unescape_mtab_field(field: &str) -> String {
+    let mut value = field.to_string().replace("\\134", "\\");
+    for ch in "\t\r\n ".chars() {
+        let replacement = ch.to_string();
+        let needle = format!("\\{:o}", ch as u8);
+        value = value.replace(&needle, &replacement);
+    }
+    value
 }
 
 /// Return a vector of MountedFilesystems relative to a given mount
-/// namespace.
+/// namespace.  We parse `/proc/{pid}/mountinfo` rather than
+/// `/proc/{pid}/mounts` because mountinfo carries the mount ID and
+/// parent mount ID of each entry, which `process_namespace()` needs in order to
+/// unmount layered mounts (e.g. a bind mount or overlay on top of a
+/// squashfuse mount) in dependency order.
 fn get_mounts(
     nsinfo: &MountNamespaceInfo,
     logger: &slog::Logger,
 ) -> Result<Vec<MountedFilesystem>> {
-    // Read the process' mounts from our own process and mount
-    // namespace.
-    let proc_mounts_path = PathBuf::from(format!("/proc/{}/mounts", nsinfo.pid));
+    let proc_mountinfo_path = PathBuf::from(format!("/proc/{}/mountinfo", nsinfo.pid));
 
-    let file = BufReader::new(File::open(proc_mounts_path)?);
+    let file = BufReader::new(File::open(proc_mountinfo_path)?);
     let mut mounts = Vec::new();
     for line in file.lines() {
         if let Ok(line) = line {
-            let mut fields = line.split(' ').skip(1).take(2).map(str::to_string);
-            // mtab can be escaped; fix it up before calling umount.
-            // Details:
-            // https://gnu.org/software/libc/manual/html_node/mtab.html
-            // Note backslashes are just '\134' and not '\0134' - special
-            // case.
-            let mut mountpoint = fields
-                .next()
-                .expect("missing mountpoint field")
-                .replace("\\134", "\\");
-            for ch in "\t\r\n ".chars() {
-                let replacement = ch.to_string();
-                let needle = format!("\\{:o}", ch as u8);
-                mountpoint = mountpoint.replace(&needle, &replacement);
+            // Format: mount_id parent_id major:minor root mount_point
+            // mount_options [optional_fields...] - fstype mount_source
+            // super_options
+            let fields: Vec<&str> = line.split(' ').collect();
+            let dash_pos = match fields.iter().position(|&f| f == "-") {
+                Some(pos) => pos,
+                None => {
+                    info!(logger, "Skipping malformed mountinfo line: {:?}", line);
+                    continue;
+                }
+            };
+            if dash_pos < 5 || fields.len() < dash_pos + 2 {
+                info!(logger, "Skipping malformed mountinfo line: {:?}", line);
+                continue;
             }
-            let fstype = fields.next().unwrap();
+            let mount_id = match u64::from_str(fields[0]) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            let parent_id = match u64::from_str(fields[1]) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            let mountpoint = unescape_mtab_field(fields[4]);
+            let fstype = fields[dash_pos + 1].to_string();
+            let propagation = if dash_pos > 6 {
+                Propagation::parse(&fields[6..dash_pos])
+            } else {
+                Propagation::Private
+            };
             mounts.push(MountedFilesystem {
                 mountpoint,
                 chroot: nsinfo.chroot_path.clone(),
                 fstype,
+                mount_id,
+                parent_id,
+                propagation,
             })
         } else if let Err(ref e) = line {
             info!(logger, "Skipping invalid line: {:?} ({})", line, e);
@@ -180,6 +286,32 @@ fn get_mounts(
     return Ok(mounts);
 }
 
+/// Number of ancestors between `mount_id` and the root of its mount
+/// tree, counted by following `parent_id` links within `id_to_parent`.
+/// A `parent_id` missing from the map (the parent lies outside the set
+/// of mounts we parsed) or pointing at itself (mountinfo's convention
+/// for the root mount) ends the walk.
+fn mount_depth(mount_id: u64, id_to_parent: &HashMap<u64, u64>) -> usize {
+    match id_to_parent.get(&mount_id) {
+        Some(&parent_id) if parent_id != mount_id => 1 + mount_depth(parent_id, id_to_parent),
+        _ => 0,
+    }
+}
+
+/// Order mounts so that descendants are unmounted before ancestors:
+/// every mount whose `parent_id` matches another candidate's
+/// `mount_id` sorts before that parent.  Mountpoint path depth isn't
+/// equivalent to this -- a bind mount or overlay can have a shallower
+/// mountpoint than the mount it's layered on -- so we instead compute
+/// each mount's real depth in its mount tree from `mount_id`/
+/// `parent_id` and sort deepest-first.
+fn order_for_unmount(mut mounts: Vec<MountedFilesystem>) -> Vec<MountedFilesystem> {
+    let id_to_parent: HashMap<u64, u64> =
+        mounts.iter().map(|m| (m.mount_id, m.parent_id)).collect();
+    mounts.sort_by_key(|m| std::cmp::Reverse(mount_depth(m.mount_id, &id_to_parent)));
+    mounts
+}
+
 // Simple test to exercise walking the host's mounts and mount
 // namespaces.
 #[test]
@@ -222,34 +354,6 @@ impl Drop for ShouldUnmountResult {
     }
 }
 
-/// A simple structure that, when created, changes to a specified
-/// mount namespace and, when drop'd, returns to the original mount
-/// namespace.
-struct NamespaceSaver {
-    orig_ns_fd: i32,
-}
-
-impl NamespaceSaver {
-    fn new(orig_ns_fd: i32, nspath: &PathBuf) -> Result<NamespaceSaver> {
-        let temp_ns_fd = nix::fcntl::open(
-            nspath,
-            nix::fcntl::OFlag::O_RDONLY,
-            nix::sys::stat::Mode::from_bits(0700).unwrap(),
-        )?;
-        nix::sched::setns(temp_ns_fd, nix::sched::CloneFlags::CLONE_NEWNS)?;
-        nix::unistd::close(temp_ns_fd).expect("close should not fail");
-
-        Ok(NamespaceSaver { orig_ns_fd })
-    }
-}
-
-impl Drop for NamespaceSaver {
-    fn drop(&mut self) {
-        nix::sched::setns(self.orig_ns_fd, nix::sched::CloneFlags::CLONE_NEWNS)
-            .expect("could not restore default mount namespace");
-    }
-}
-
 #[test]
 fn get_lockfile_test() {
     // Helper to make a MountedFilesystem object.
@@ -258,6 +362,9 @@ fn get_lockfile_test() {
             mountpoint: String::from(mountpoint),
             chroot: PathBuf::from("/"),
             fstype: String::from("fuse.squashfuse_ll"),
+            mount_id: 0,
+            parent_id: 0,
+            propagation: Propagation::Private,
         }
     }
 
@@ -383,7 +490,7 @@ fn should_unmount(
     }
     info!(
         logger,
-        "Considering {} ({})", mount.mountpoint, mount.fstype
+        "Considering {} ({}), propagation: {:?}", mount.mountpoint, mount.fstype, mount.propagation
     );
 
     let lockfiles = get_lockfile_path(&logger, &mount);
@@ -436,7 +543,7 @@ fn should_unmount(
 
     // lock the file before checking timestamp to protect against a
     // race with XarexecFuse.
-    if !flock_with_timeout(lock_fd, 60) {
+    if !flock_with_timeout(lock_fd, 60, LockMode::Exclusive) {
         info!(
             logger,
             "Unable to flock {:?}, skipping...", chrooted_mountpoint
@@ -460,6 +567,207 @@ fn should_unmount(
     Ok(ShouldUnmountResult::new(true, lock_opt))
 }
 
+/// Fork a single-threaded child that enters `nsinfo`'s mount
+/// namespace, chroots into `/proc/{pid}/root`, and unmounts
+/// `mountpoint` as seen from inside that root rather than guessing a
+/// host path by concatenating the process' chroot onto it (fragile
+/// whenever the chroot isn't a simple prefix, e.g. pivot_root'd
+/// containers).  We have to do this work in a forked child because
+/// `setns` fails with `EINVAL` on a multi-threaded process (see the
+/// `malloc_conf` comment above).  The child reports its result via
+/// `_exit`'s status code rather than returning, so it never continues
+/// into the caller's code.  Returns whether the child reported a
+/// successful unmount.  Only called when running as root, since
+/// `setns`/`chroot` require it.
+fn unmount_in_namespace(
+    logger: &slog::Logger,
+    nsinfo: &MountNamespaceInfo,
+    mountpoint: &str,
+    propagation: Propagation,
+    opts: UnmountOptions,
+) -> Result<bool> {
+    match unsafe { nix::unistd::fork() }? {
+        nix::unistd::ForkResult::Child => {
+            let status = match unmount_in_namespace_child(nsinfo, mountpoint, propagation, opts) {
+                Ok(true) => 0,
+                Ok(false) | Err(_) => 1,
+            };
+            unsafe { libc::_exit(status) };
+        }
+        nix::unistd::ForkResult::Parent { child } => match nix::sys::wait::waitpid(child, None)? {
+            nix::sys::wait::WaitStatus::Exited(_, 0) => {
+                info!(
+                    logger,
+                    "Unmounted {:?} in {:?}", mountpoint, nsinfo.namespace_path
+                );
+                Ok(true)
+            }
+            status => {
+                info!(
+                    logger,
+                    "Failed to unmount {:?} in {:?}: child exited with {:?}",
+                    mountpoint,
+                    nsinfo.namespace_path,
+                    status
+                );
+                Ok(false)
+            }
+        },
+    }
+}
+
+/// Child-side work for `unmount_in_namespace`: enter the mount
+/// namespace, chroot into the process' root, and unmount.  Returns
+/// whether the unmount actually succeeded, so the parent can tell a
+/// real success apart from a child that exited 0 after merely failing
+/// to unmount (`unmount_target` logs failures but doesn't error out).
+fn unmount_in_namespace_child(
+    nsinfo: &MountNamespaceInfo,
+    mountpoint: &str,
+    propagation: Propagation,
+    opts: UnmountOptions,
+) -> Result<bool> {
+    let ns_fd = nix::fcntl::open(
+        &nsinfo.namespace_path,
+        nix::fcntl::OFlag::O_RDONLY,
+        nix::sys::stat::Mode::from_bits(0700).unwrap(),
+    )?;
+    nix::sched::setns(ns_fd, nix::sched::CloneFlags::CLONE_NEWNS)?;
+    nix::unistd::close(ns_fd)?;
+
+    let proc_root = PathBuf::from(format!("/proc/{}/root", nsinfo.pid));
+    nix::unistd::chroot(&proc_root)?;
+    nix::unistd::chdir("/")?;
+
+    let logger = setup_logger(slog::Level::Info);
+    let target = PathBuf::from(mountpoint);
+
+    // A shared mount forwards unmount events to its peer group; make
+    // it private first so our unmount stays local to this namespace
+    // and doesn't tear down the same XAR mounted elsewhere.
+    if propagation.is_shared() && opts.isolate_propagation {
+        match nix::mount::mount(
+            None::<&str>,
+            &target,
+            None::<&str>,
+            nix::mount::MsFlags::MS_PRIVATE | nix::mount::MsFlags::MS_REC,
+            None::<&str>,
+        ) {
+            Ok(()) => info!(logger, "Made shared mount {:?} private", target),
+            Err(e) => info!(logger, "Failed to make {:?} private: {}", target, e),
+        }
+    }
+
+    unmount_target(&logger, &target, opts)
+}
+
+/// Unmount `target`, trying progressively more aggressive strategies.
+/// As root, a plain `umount2` is tried first and, on `EBUSY`, retried
+/// with `MNT_DETACH` (if `lazy`) and then `MNT_FORCE` (if `force`).
+/// Without root, we shell out to `fusermount`, whose `-z` flag is the
+/// equivalent of `MNT_DETACH`; `fusermount` has no force option, so
+/// `force` only takes effect when running as root.  Returns whether
+/// `target` actually ended up unmounted.
+fn unmount_target(logger: &slog::Logger, target: &PathBuf, opts: UnmountOptions) -> Result<bool> {
+    if nix::unistd::geteuid().is_root() {
+        if let Err(e) = nix::mount::umount2(target, nix::mount::MntFlags::empty()) {
+            if e != nix::Error::Sys(nix::errno::Errno::EBUSY) {
+                info!(logger, "Failed to unmount {:?}: {}", target, e);
+                return Ok(false);
+            }
+            if opts.lazy {
+                match nix::mount::umount2(target, nix::mount::MntFlags::MNT_DETACH) {
+                    Ok(()) => {
+                        info!(logger, "Lazily detached busy mount {:?}", target);
+                        return Ok(true);
+                    }
+                    Err(e) => info!(logger, "Lazy detach of {:?} failed: {}", target, e),
+                }
+            }
+            if opts.force {
+                return match nix::mount::umount2(target, nix::mount::MntFlags::MNT_FORCE) {
+                    Ok(()) => {
+                        info!(logger, "Force unmounted busy mount {:?}", target);
+                        Ok(true)
+                    }
+                    Err(e) => {
+                        info!(logger, "Failed to force unmount {:?}: {}", target, e);
+                        Ok(false)
+                    }
+                };
+            } else if !opts.lazy {
+                info!(logger, "Failed to unmount {:?}: {}", target, e);
+            }
+            Ok(false)
+        } else {
+            info!(logger, "Unmounted {:?}", target);
+            Ok(true)
+        }
+    } else {
+        let mut args = vec!["-u"];
+        if opts.lazy {
+            args.push("-z");
+        }
+        let output = Command::new("fusermount")
+            .args(&args)
+            .arg(target)
+            .output()?;
+        if !output.status.success() {
+            info!(
+                logger,
+                "fusermount {} failed to unmount {:?}: {}",
+                args.join(" "),
+                target,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Ok(false)
+        } else {
+            info!(logger, "Unmounted {:?} via fusermount", target);
+            Ok(true)
+        }
+    }
+}
+
+/// Candidate paths for the process-wide coordination lock, in
+/// preference order.  `/run` is the conventional home for this kind
+/// of runtime state, with `/dev/shm` as a fallback on hosts where
+/// it isn't writable.
+const SINGLETON_LOCK_PATHS: &[&str] = &["/run/xar_cleaner.lock", "/dev/shm/xar_cleaner.lock"];
+
+/// Acquire the well-known, process-wide lock that keeps two
+/// `clean_xar_mounts` invocations (e.g. an overlapping cron run and a
+/// manual run) from racing on the same per-mount lockfiles.  A normal
+/// reap takes it exclusively; callers that only want to observe state
+/// (e.g. `--dry-run`) can take it shared so they don't block each
+/// other while still excluding a real reap.  Returns `None` if another
+/// instance already holds it, in which case the caller should exit
+/// cleanly rather than erroring.
+fn acquire_singleton_lock(logger: &slog::Logger, mode: LockMode) -> Result<Option<RawFd>> {
+    let mut last_err = None;
+    for path in SINGLETON_LOCK_PATHS {
+        let fd = match nix::fcntl::open(
+            *path,
+            nix::fcntl::OFlag::O_RDWR | nix::fcntl::OFlag::O_CREAT | nix::fcntl::OFlag::O_CLOEXEC,
+            nix::sys::stat::Mode::from_bits(0o600).unwrap(),
+        ) {
+            Ok(fd) => fd,
+            Err(e) => {
+                debug!(logger, "Unable to open lock file {}: {}", path, e);
+                last_err = Some(e);
+                continue;
+            }
+        };
+        if flock_with_timeout(fd, 1, mode) {
+            debug!(logger, "Acquired singleton lock {}", path);
+            return Ok(Some(fd));
+        }
+        nix::unistd::close(fd).ok();
+        info!(logger, "Another instance already holds {}, exiting", path);
+        return Ok(None);
+    }
+    Err(last_err.expect("at least one candidate lock path").into())
+}
+
 fn setup_logger(level: slog::Level) -> slog::Logger {
     let drain = slog_term::PlainSyncDecorator::new(std::io::stdout());
     let drain = slog_term::FullFormat::new(drain).build();
@@ -467,6 +775,132 @@ fn setup_logger(level: slog::Level) -> slog::Logger {
     slog::Logger::root(drain, o![])
 }
 
+/// Unmount every stale mount found in `nsinfo`.  Runs inside a worker
+/// forked by `clean_namespaces`.  Does not itself `setns` into
+/// `nsinfo`: `get_mounts` reads the namespace's mountinfo straight out
+/// of `/proc/{pid}/mountinfo`, `should_unmount` resolves paths through
+/// the process' `/proc/{pid}/root`, and, as root, `unmount_in_namespace`
+/// forks its own single-threaded child to enter the namespace for the
+/// actual unmount.  Returns the number of mounts reaped.
+fn process_namespace(
+    logger: &slog::Logger,
+    nsinfo: &MountNamespaceInfo,
+    timeout: u32,
+    opts: UnmountOptions,
+) -> Result<usize> {
+    info!(logger, "Entering namespace {:?}...", nsinfo.namespace_path);
+    let mounts = get_mounts(nsinfo, logger)?;
+
+    let mut reaped = 0;
+    for mount in order_for_unmount(mounts) {
+        let result = should_unmount(logger, &mount, timeout)?;
+        if result.should_unmount {
+            info!(
+                logger,
+                "unmounting {:?}:{:?}", nsinfo.namespace_path, mount.mountpoint
+            );
+            if !opts.dryrun {
+                let unmounted = if nix::unistd::geteuid().is_root() {
+                    unmount_in_namespace(
+                        logger,
+                        nsinfo,
+                        &mount.mountpoint,
+                        mount.propagation,
+                        opts,
+                    )?
+                } else {
+                    if mount.propagation.is_shared() {
+                        info!(
+                            logger,
+                            "{:?} is a shared mount; cannot isolate propagation without \
+                             root, unmount may affect other namespaces",
+                            mount.mountpoint
+                        );
+                    }
+                    // fusermount has no notion of entering another
+                    // mount namespace, so fall back to guessing the
+                    // host path for the unprivileged case.
+                    let mut target = mount.chroot.clone();
+                    target.push(&mount.mountpoint[1..]); // strip leading slash
+                    unmount_target(logger, &target, opts)?
+                };
+                if unmounted {
+                    reaped += 1;
+                }
+            }
+        }
+    }
+    Ok(reaped)
+}
+
+/// Process each of `mount_namespaces` in its own forked worker (we
+/// can't use threads: `setns` fails with `EINVAL` on a multi-threaded
+/// process).  Up to `jobs` workers run at once, classic
+/// jobserver-style: we hold up to `jobs` tokens (outstanding
+/// children), and fork the next pending namespace as soon as one
+/// exits and its token is released.  Each worker reports back how
+/// many mounts it reaped over a pipe.
+fn clean_namespaces(
+    logger: &slog::Logger,
+    mount_namespaces: Vec<MountNamespaceInfo>,
+    jobs: usize,
+    timeout: u32,
+    opts: UnmountOptions,
+) -> Result<()> {
+    let mut pending = mount_namespaces.into_iter();
+    let mut outstanding: HashMap<nix::unistd::Pid, (RawFd, PathBuf)> = HashMap::new();
+
+    loop {
+        while outstanding.len() < jobs {
+            let nsinfo = match pending.next() {
+                Some(nsinfo) => nsinfo,
+                None => break,
+            };
+            let (read_fd, write_fd) = nix::unistd::pipe()?;
+            // The child reports its result over `write_fd` and
+            // `_exit`s rather than returning, so it never runs the rest
+            // of this loop.
+            match unsafe { nix::unistd::fork() }? {
+                nix::unistd::ForkResult::Child => {
+                    nix::unistd::close(read_fd).ok();
+                    let count = process_namespace(logger, &nsinfo, timeout, opts).unwrap_or(0);
+                    let _ = nix::unistd::write(write_fd, &(count as u64).to_ne_bytes());
+                    nix::unistd::close(write_fd).ok();
+                    unsafe { libc::_exit(0) };
+                }
+                nix::unistd::ForkResult::Parent { child } => {
+                    nix::unistd::close(write_fd)?;
+                    outstanding.insert(child, (read_fd, nsinfo.namespace_path));
+                }
+            }
+        }
+
+        if outstanding.is_empty() {
+            break;
+        }
+
+        let status = nix::sys::wait::waitpid(nix::unistd::Pid::from_raw(-1), None)?;
+        let pid = match status {
+            nix::sys::wait::WaitStatus::Exited(pid, _) => pid,
+            nix::sys::wait::WaitStatus::Signaled(pid, _, _) => pid,
+            _ => continue,
+        };
+        if let Some((read_fd, namespace_path)) = outstanding.remove(&pid) {
+            let mut buf = [0u8; 8];
+            let count = match nix::unistd::read(read_fd, &mut buf) {
+                Ok(8) => u64::from_ne_bytes(buf),
+                _ => 0,
+            };
+            nix::unistd::close(read_fd).ok();
+            info!(
+                logger,
+                "Namespace {:?} reaped {} mount(s)", namespace_path, count
+            );
+        }
+    }
+    Ok(())
+}
+
 // This is our main function.
 fn run() -> Result<()> {
     let matches = App::new("Clean XAR Mounts")
@@ -487,9 +921,44 @@ fn run() -> Result<()> {
                 .long("dry-run")
                 .help("display detailed output"),
         )
+        .arg(
+            Arg::with_name("lazy")
+                .long("lazy")
+                .help("retry a busy unmount with a lazy detach (MNT_DETACH); without root, pass -z to fusermount")
+                .default_value("true")
+                .possible_values(&["true", "false"]),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("if a lazy detach still fails, force the unmount with MNT_FORCE (root only)"),
+        )
+        .arg(
+            Arg::with_name("isolate-propagation")
+                .long("isolate-propagation")
+                .help(
+                    "remount a shared mount MS_PRIVATE before unmounting it, so the unmount \
+                     doesn't propagate into peer mount namespaces still using the XAR (root only)",
+                )
+                .default_value("true")
+                .possible_values(&["true", "false"]),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .default_value("1")
+                .help("number of mount namespaces to clean up in parallel"),
+        )
         .get_matches();
     let timeout = value_t!(matches, "timeout", u32)?;
-    let dryrun = matches.is_present("dryrun");
+    let jobs = std::cmp::max(value_t!(matches, "jobs", usize)?, 1);
+    let opts = UnmountOptions {
+        dryrun: matches.is_present("dryrun"),
+        lazy: value_t!(matches, "lazy", bool)?,
+        force: matches.is_present("force"),
+        isolate_propagation: value_t!(matches, "isolate-propagation", bool)?,
+    };
     let level = if matches.is_present("verbose") {
         slog::Level::Debug
     } else {
@@ -498,75 +967,24 @@ fn run() -> Result<()> {
 
     let root_log = setup_logger(level);
 
-    let orig_ns_fd = nix::fcntl::open(
-        "/proc/self/ns/mnt",
-        nix::fcntl::OFlag::O_RDONLY,
-        nix::sys::stat::Mode::from_bits(0700).unwrap(),
-    )?;
+    let lock_mode = if opts.dryrun {
+        LockMode::Shared
+    } else {
+        LockMode::Exclusive
+    };
+    let _singleton_lock = match acquire_singleton_lock(&root_log, lock_mode)? {
+        Some(fd) => fd,
+        None => return Ok(()),
+    };
+
     let mount_namespaces = get_mount_namespaces()?;
     info!(
         root_log,
-        "Considering {} namespaces",
-        mount_namespaces.len()
+        "Considering {} namespaces using {} worker(s)",
+        mount_namespaces.len(),
+        jobs
     );
-    for nsinfo in mount_namespaces {
-        info!(
-            root_log,
-            "Entering namespace {:?}...", nsinfo.namespace_path
-        );
-        // Enter the new namespace and then check /proc/mounts for the
-        // now-visible mounts.
-        let mounts = get_mounts(&nsinfo, &root_log);
-        if mounts.is_err() {
-            info!(
-                root_log,
-                "Unable to read mounts in {:?}", nsinfo.namespace_path
-            );
-            continue;
-        }
-        let _ns_saver = NamespaceSaver::new(orig_ns_fd, &nsinfo.namespace_path);
-        if _ns_saver.is_err() && nix::unistd::geteuid().is_root() {
-            info!(
-                root_log,
-                "Unable to enter namespace {:?}, skipping", nsinfo.namespace_path
-            );
-            continue;
-        }
-        for mount in mounts.unwrap() {
-            let result = should_unmount(&root_log, &mount, timeout)?;
-            if result.should_unmount {
-                // TODO: consider forking and chrooting into the
-                // process' chroot rather than constructing a path
-                // from outside.  It may not always be true that we
-                // can append paths to find the actual mount point to
-                // unmount.
-                let mut target = mount.chroot.clone();
-                target.push(&mount.mountpoint[1..]); // strip leading slash
-                info!(
-                    root_log,
-                    "unmounting {:?}:{:?}", nsinfo.namespace_path, target
-                );
-                if !dryrun {
-                    if nix::unistd::geteuid().is_root() {
-                        if let Err(e) = nix::mount::umount(&target) {
-                            info!(root_log, "Failed to unmount {:?}: {}", target, e);
-                        }
-                    } else {
-                        let output = Command::new("fusermount").arg("-u").arg(&target).output()?;
-                        if !output.status.success() {
-                            info!(
-                                root_log,
-                                "fusermount -u failed to unmount {:?}: {}",
-                                target,
-                                String::from_utf8_lossy(&output.stderr).trim()
-                            );
-                        }
-                    }
-                }
-            }
-        }
-    }
-    Ok(())
+    clean_namespaces(&root_log, mount_namespaces, jobs, timeout, opts)
 }
 
 // Boilerplate main to print errors nicely.